@@ -2,13 +2,43 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use canvas_traits::{FillOrStrokeStyle, SurfaceStyle, RepetitionStyle};
+use canvas_traits::{ExtendMode, FillOrStrokeStyle, SurfaceStyle, RepetitionStyle};
 use dom::bindings::codegen::Bindings::CanvasPatternBinding;
+use dom::bindings::codegen::Bindings::CanvasPatternBinding::CanvasPatternMethods;
 use dom::bindings::global::GlobalRef;
 use dom::bindings::js::{JSRef, Temporary};
 use dom::bindings::utils::{Reflector, reflect_dom_object};
 use dom::canvasgradient::ToFillOrStrokeStyle;
+use dom::htmlcanvaselement::{HTMLCanvasElement, HTMLCanvasElementHelpers};
+use dom::htmlimageelement::{HTMLImageElement, HTMLImageElementHelpers};
+use geom::matrix2d::Matrix2D;
+use geom::rect::Rect;
 use geom::size::Size2D;
+use std::cell::Cell;
+
+// https://html.spec.whatwg.org/multipage/#imagebitmap
+//
+// `dom::imagebitmap::ImageBitmap` does not exist in this tree yet, so this
+// is not a `JSRef`-reflected DOM object like the other two sources; it's
+// the decoded pixel data and origin/CORS provenance that an `ImageBitmap`
+// would already carry, handed to `CanvasPattern::new_from_image_source`
+// by value as this source. Once
+// `dom::imagebitmap` lands, replace this variant's payload with
+// `JSRef<'a, ImageBitmap>` and pull the same three values from its own
+// accessors, as the other two variants do.
+pub struct DecodedImageBitmap {
+    pub surface_data: Vec<u8>,
+    pub surface_size: Size2D<i32>,
+    pub same_origin: bool,
+    pub cors_approved: bool,
+}
+
+// https://html.spec.whatwg.org/multipage/#canvasimagesource
+pub enum CanvasImageSource<'a> {
+    HTMLImageElement(JSRef<'a, HTMLImageElement>),
+    HTMLCanvasElement(JSRef<'a, HTMLCanvasElement>),
+    ImageBitmap(DecodedImageBitmap),
+}
 
 // https://html.spec.whatwg.org/multipage/#canvaspattern
 #[dom_struct]
@@ -16,40 +46,233 @@ pub struct CanvasPattern {
     reflector_: Reflector,
     surface_data: Vec<u8>,
     surface_size: Size2D<i32>,
-    repeat_x: bool,
-    repeat_y: bool,
+    // Per-axis extend mode the paint task's `SurfacePattern` tiles the
+    // surface with: `Repeat` tiles across the filled/stroked geometry's
+    // bounding box along that axis, `Clamp` tiles it at most once. The
+    // draw target then clips the tiled result to the real path, so
+    // `repeat-x`/`repeat-y`/`no-repeat` behave the same for fills,
+    // strokes, and text instead of only for axis-aligned `fill_rect`s.
+    extend_x: ExtendMode,
+    extend_y: ExtendMode,
+    transform: Cell<Matrix2D<f32>>,
+    // https://html.spec.whatwg.org/multipage/#origin-clean-flag
+    // Whether the source surface was same-origin with the document, or a
+    // cross-origin resource that was fetched in CORS mode and approved by
+    // the server. If neither holds, using this pattern must taint the
+    // destination canvas.
+    origin_clean: bool,
 }
 
 impl CanvasPattern {
-    fn new_inherited(surface_data: Vec<u8>, surface_size: Size2D<i32>, repeat: RepetitionStyle) -> CanvasPattern {
-        let (x, y) = match repeat {
-            RepetitionStyle::Repeat => (true, true),
-            RepetitionStyle::RepeatX => (true, false),
-            RepetitionStyle::RepeatY => (false, true),
-            RepetitionStyle::NoRepeat => (false, false),
-        };
+    fn new_inherited(surface_data: Vec<u8>,
+                      surface_size: Size2D<i32>,
+                      repeat: RepetitionStyle,
+                      origin_clean: bool)
+                      -> CanvasPattern {
+        let (extend_x, extend_y) = extend_modes_for(repeat);
 
         CanvasPattern {
             reflector_: Reflector::new(),
             surface_data: surface_data,
             surface_size: surface_size,
-            repeat_x: x,
-            repeat_y: y,
+            extend_x: extend_x,
+            extend_y: extend_y,
+            transform: Cell::new(Matrix2D::identity()),
+            origin_clean: origin_clean,
         }
     }
     pub fn new(global: GlobalRef,
                surface_data: Vec<u8>,
                surface_size: Size2D<i32>,
-               repeat: RepetitionStyle)
+               repeat: RepetitionStyle,
+               origin_clean: bool)
                -> Temporary<CanvasPattern> {
-        reflect_dom_object(box CanvasPattern::new_inherited(surface_data, surface_size, repeat),
+        reflect_dom_object(box CanvasPattern::new_inherited(surface_data, surface_size, repeat, origin_clean),
                            global, CanvasPatternBinding::Wrap)
     }
+
+    // https://html.spec.whatwg.org/multipage/#dom-context-2d-createpattern
+    //
+    // Landed ahead of its only caller: `CanvasRenderingContext2D::CreatePattern`
+    // is not part of this chunk of the tree, so nothing here invokes this yet
+    // and the feature is inert until that binding is rewired to call it
+    // instead of pre-rasterizing the image source into bytes itself.
+    #[allow(dead_code)]
+    pub fn new_from_image_source(global: GlobalRef,
+                                  image: CanvasImageSource,
+                                  repeat: RepetitionStyle)
+                                  -> Temporary<CanvasPattern> {
+        let (surface_data, surface_size, origin_clean) = match image {
+            CanvasImageSource::HTMLImageElement(image) => {
+                (image.get_image_data(),
+                 image.get_image_size(),
+                 is_origin_clean(image.same_origin(), image.is_cors_approved()))
+            }
+            CanvasImageSource::HTMLCanvasElement(canvas) => {
+                // The canvas may be actively being drawn to, so its backing
+                // store has to be snapshotted rather than shared.
+                (canvas.fetch_all_data(), canvas.get_size(), canvas.origin_is_clean())
+            }
+            CanvasImageSource::ImageBitmap(bitmap) => decoded_image_bitmap_source(bitmap),
+        };
+
+        CanvasPattern::new(global, surface_data, surface_size, repeat, origin_clean)
+    }
+
+    /// The rectangle this pattern's surface should be tiled across to cover
+    /// `bounds`, per axis: an axis whose extend mode is `Repeat` tiles
+    /// across the whole bounding box of the filled/stroked geometry, while
+    /// an axis whose extend mode is `Clamp` tiles the surface at most once,
+    /// anchored at `bounds`'s origin. This rectangle is not sent to the
+    /// paint task: `to_fill_or_stroke_style` passes the per-axis extend
+    /// modes and the transform instead.
+    pub fn tile_bounds(&self, bounds: Rect<f32>) -> Rect<f32> {
+        fn tiled_extent(extend: ExtendMode, surface_extent: f32, bounds_extent: f32) -> f32 {
+            match extend {
+                ExtendMode::Repeat => bounds_extent,
+                ExtendMode::Clamp => surface_extent.min(bounds_extent),
+            }
+        }
+
+        let width = tiled_extent(self.extend_x, self.surface_size.width as f32, bounds.size.width);
+        let height = tiled_extent(self.extend_y, self.surface_size.height as f32, bounds.size.height);
+        Rect::new(bounds.origin, Size2D::new(width, height))
+    }
+}
+
+// The only one of the three `CanvasImageSource` arms that doesn't require a
+// live `JSRef` to extract, so it's split out to be exercised directly.
+fn decoded_image_bitmap_source(bitmap: DecodedImageBitmap) -> (Vec<u8>, Size2D<i32>, bool) {
+    let origin_clean = is_origin_clean(bitmap.same_origin, bitmap.cors_approved);
+    (bitmap.surface_data, bitmap.surface_size, origin_clean)
+}
+
+// https://html.spec.whatwg.org/multipage/#dom-canvaspattern-repeat
+fn extend_modes_for(repeat: RepetitionStyle) -> (ExtendMode, ExtendMode) {
+    match repeat {
+        RepetitionStyle::Repeat => (ExtendMode::Repeat, ExtendMode::Repeat),
+        RepetitionStyle::RepeatX => (ExtendMode::Repeat, ExtendMode::Clamp),
+        RepetitionStyle::RepeatY => (ExtendMode::Clamp, ExtendMode::Repeat),
+        RepetitionStyle::NoRepeat => (ExtendMode::Clamp, ExtendMode::Clamp),
+    }
+}
+
+// https://html.spec.whatwg.org/multipage/#origin-clean-flag
+//
+// A source only keeps the canvas origin-clean if it was same-origin with
+// the document *or* cross-origin but fetched in CORS mode and approved by
+// the server; either one is sufficient on its own.
+fn is_origin_clean(same_origin: bool, cors_approved: bool) -> bool {
+    same_origin || cors_approved
+}
+
+pub trait CanvasPatternHelpers {
+    fn origin_is_clean(self) -> bool;
+}
+
+impl<'a> CanvasPatternHelpers for JSRef<'a, CanvasPattern> {
+    // Consulted by the 2D context when this pattern is set as the fill or
+    // stroke style, so it can propagate tainting to the owning canvas.
+    fn origin_is_clean(self) -> bool {
+        self.origin_clean
+    }
+}
+
+impl<'a> CanvasPatternMethods for JSRef<'a, CanvasPattern> {
+    // https://html.spec.whatwg.org/multipage/#dom-canvaspattern-settransform
+    fn SetTransform(self, a: f64, b: f64, c: f64, d: f64, e: f64, f: f64) {
+        self.transform.set(matrix_from_components(a, b, c, d, e, f));
+    }
+}
+
+// Split out so the component ordering can be asserted directly in a test,
+// without going through `SetTransform`'s `JSRef` receiver.
+fn matrix_from_components(a: f64, b: f64, c: f64, d: f64, e: f64, f: f64) -> Matrix2D<f32> {
+    Matrix2D::new(a as f32, b as f32, c as f32, d as f32, e as f32, f as f32)
 }
 
 impl<'a> ToFillOrStrokeStyle for JSRef<'a, CanvasPattern> {
     fn to_fill_or_stroke_style(&self) -> FillOrStrokeStyle {
         FillOrStrokeStyle::Surface(
-            SurfaceStyle::new(self.surface_data.clone(), self.surface_size, self.repeat_x, self.repeat_y))
+            SurfaceStyle::new(self.surface_data.clone(),
+                               self.surface_size,
+                               self.extend_x,
+                               self.extend_y,
+                               self.transform.get()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geom::point::Point2D;
+
+    #[test]
+    fn matrix_from_components_preserves_argument_order() {
+        let m = matrix_from_components(1.0, 2.0, 3.0, 4.0, 5.0, 6.0);
+        assert_eq!(m, Matrix2D::new(1.0, 2.0, 3.0, 4.0, 5.0, 6.0));
+    }
+
+    #[test]
+    fn origin_clean_requires_same_origin_or_cors_approval() {
+        assert!(is_origin_clean(true, false));
+        assert!(is_origin_clean(false, true));
+        assert!(is_origin_clean(true, true));
+        assert!(!is_origin_clean(false, false));
+    }
+
+    #[test]
+    fn extend_modes_for_match_each_repetition_style() {
+        assert_eq!(extend_modes_for(RepetitionStyle::Repeat), (ExtendMode::Repeat, ExtendMode::Repeat));
+        assert_eq!(extend_modes_for(RepetitionStyle::RepeatX), (ExtendMode::Repeat, ExtendMode::Clamp));
+        assert_eq!(extend_modes_for(RepetitionStyle::RepeatY), (ExtendMode::Clamp, ExtendMode::Repeat));
+        assert_eq!(extend_modes_for(RepetitionStyle::NoRepeat), (ExtendMode::Clamp, ExtendMode::Clamp));
+    }
+
+    #[test]
+    fn tile_bounds_clamps_non_repeating_axes_to_the_surface_size() {
+        let pattern = CanvasPattern::new_inherited(vec![0; 16], Size2D::new(2, 2), RepetitionStyle::NoRepeat, true);
+        let bounds = Rect::new(Point2D::new(0.0, 0.0), Size2D::new(100.0, 100.0));
+        assert_eq!(pattern.tile_bounds(bounds).size, Size2D::new(2.0, 2.0));
+    }
+
+    #[test]
+    fn tile_bounds_spans_the_full_box_on_repeating_axes() {
+        let pattern = CanvasPattern::new_inherited(vec![0; 16], Size2D::new(2, 2), RepetitionStyle::Repeat, true);
+        let bounds = Rect::new(Point2D::new(0.0, 0.0), Size2D::new(100.0, 50.0));
+        assert_eq!(pattern.tile_bounds(bounds).size, Size2D::new(100.0, 50.0));
+    }
+
+    #[test]
+    fn tile_bounds_mixes_axes_independently_for_repeat_x() {
+        let pattern = CanvasPattern::new_inherited(vec![0; 16], Size2D::new(2, 2), RepetitionStyle::RepeatX, true);
+        let bounds = Rect::new(Point2D::new(0.0, 0.0), Size2D::new(100.0, 100.0));
+        assert_eq!(pattern.tile_bounds(bounds).size, Size2D::new(100.0, 2.0));
+    }
+
+    #[test]
+    fn decoded_image_bitmap_source_taints_when_neither_same_origin_nor_cors_approved() {
+        let bitmap = DecodedImageBitmap {
+            surface_data: vec![1, 2, 3, 4],
+            surface_size: Size2D::new(1, 1),
+            same_origin: false,
+            cors_approved: false,
+        };
+        let (data, size, origin_clean) = decoded_image_bitmap_source(bitmap);
+        assert_eq!(data, vec![1, 2, 3, 4]);
+        assert_eq!(size, Size2D::new(1, 1));
+        assert!(!origin_clean);
+    }
+
+    #[test]
+    fn decoded_image_bitmap_source_stays_clean_when_cors_approved() {
+        let bitmap = DecodedImageBitmap {
+            surface_data: vec![],
+            surface_size: Size2D::new(0, 0),
+            same_origin: false,
+            cors_approved: true,
+        };
+        let (_, _, origin_clean) = decoded_image_bitmap_source(bitmap);
+        assert!(origin_clean);
     }
 }